@@ -0,0 +1,98 @@
+use std::hash::Hasher;
+
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// Column family holding the serialized hash bloom filter.
+pub const HASHES_BLOOM: &str = "hashes_bloom";
+
+/// A partitioned bloom filter summarizing the set of hashes present in the
+/// HASHES column family, so `counter_for_query` can skip the RocksDB lookup
+/// for hashes that are definitely absent.
+///
+/// The `m` bits are split into `k` partitions of `m / k` bits, one per hash
+/// function. The `k` bit positions for a key are derived from two fast base
+/// hashes (xxh3 and farmhash) via double hashing: `h_i = h1 + i*h2`, taken
+/// modulo `m / k` within partition `i`.
+#[derive(Debug, PartialEq, Clone, Archive, Serialize, Deserialize)]
+pub struct Bloom {
+    /// Bit array, packed into 64-bit words.
+    bits: Vec<u64>,
+    /// Number of bits `m` in the filter.
+    num_bits: u64,
+    /// Number of hash functions `k`.
+    num_hashes: u32,
+}
+
+impl Bloom {
+    /// Build an empty filter sized for `expected_items` at the target false
+    /// positive rate `fp_rate` (e.g. `0.01` for ~1%).
+    pub fn new(expected_items: u64, fp_rate: f64) -> Self {
+        let n = expected_items.max(1) as f64;
+        // Optimal bit count and hash count for a bloom filter.
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(n * fp_rate.ln()) / (ln2 * ln2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().max(1.0) as u32;
+        let words = (num_bits as usize + 63) / 64;
+        Bloom {
+            bits: vec![0u64; words],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    fn base_hashes(key: u64) -> (u64, u64) {
+        let bytes = key.to_le_bytes();
+
+        let mut xxh = twox_hash::xxh3::Hash64::default();
+        xxh.write(&bytes);
+        let h1 = xxh.finish();
+
+        let h2 = farmhash::hash64(&bytes);
+        (h1, h2)
+    }
+
+    fn indices(&self, key: u64) -> impl Iterator<Item = u64> + '_ {
+        let (h1, h2) = Bloom::base_hashes(key);
+        let part = self.num_bits / self.num_hashes as u64;
+        (0..self.num_hashes as u64)
+            .map(move |i| i * part + h1.wrapping_add(i.wrapping_mul(h2)) % part)
+    }
+
+    /// Record that `key` is present.
+    pub fn insert(&mut self, key: u64) {
+        for bit in self.indices(key) {
+            self.bits[(bit / 64) as usize] |= 1u64 << (bit % 64);
+        }
+    }
+
+    /// Return `false` only if `key` is definitely absent.
+    pub fn contains(&self, key: u64) -> bool {
+        self.indices(key)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1u64 << (bit % 64)) != 0)
+    }
+
+    /// Fraction of bits currently set.
+    pub fn occupancy(&self) -> f64 {
+        let set: u64 = self.bits.iter().map(|w| w.count_ones() as u64).sum();
+        set as f64 / self.num_bits as f64
+    }
+
+    /// Estimated false positive rate given current occupancy.
+    pub fn estimated_fp_rate(&self) -> f64 {
+        self.occupancy().powi(self.num_hashes as i32)
+    }
+
+    pub fn from_slice(slice: &[u8]) -> Option<Self> {
+        let mut vec = rkyv::AlignedVec::new();
+        vec.extend_from_slice(slice);
+        let archived_value = unsafe { rkyv::archived_root::<Bloom>(vec.as_ref()) };
+        let inner = archived_value.deserialize(&mut rkyv::Infallible).unwrap();
+        Some(inner)
+    }
+
+    pub fn as_bytes(&self) -> Option<Vec<u8>> {
+        let bytes = rkyv::to_bytes::<_, 256>(self).unwrap();
+        Some(bytes.into_vec())
+    }
+}
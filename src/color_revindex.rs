@@ -0,0 +1,561 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use rayon::prelude::*;
+use rocksdb::Options;
+
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::bloom::{Bloom, HASHES_BLOOM};
+use crate::manifest::{ManifestRow, Picklist, MANIFEST};
+use crate::{
+    be_bytes, from_be_bytes, sig_save_to_db, DatasetID, Datasets, GatherResult, HashToColor,
+    QueryColors, RevIndex, SigCounter, SignatureData, COLORS, DB, HASHES, SIGS,
+};
+
+pub type Color = u64;
+
+/// Key under which the HASHES_BLOOM CF stores the single serialized filter.
+const BLOOM_KEY: &[u8] = b"bloom";
+const BLOOM_FP_RATE: f64 = 0.01;
+
+/// Column families opened for a colors-backed index.
+fn cf_names() -> Vec<&'static str> {
+    vec![HASHES, COLORS, SIGS, HASHES_BLOOM, MANIFEST]
+}
+
+/// A `RevIndex` that deduplicates shared dataset sets behind a `Color`: every
+/// hash in the HASHES CF points at a `Color`, and the COLORS CF maps each
+/// `Color` to the `Datasets` it covers. Hashes that map to the same set of
+/// datasets share one color, so the set is stored once instead of once per hash.
+pub struct ColorRevIndex {
+    db: Arc<DB>,
+}
+
+impl ColorRevIndex {
+    pub fn open(index: &Path, read_only: bool) -> RevIndex {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+
+        let db = if read_only {
+            Arc::new(
+                DB::open_cf_for_read_only(&opts, index, cf_names(), true)
+                    .expect("error opening database"),
+            )
+        } else {
+            Arc::new(DB::open_cf(&opts, index, cf_names()).expect("error opening database"))
+        };
+
+        RevIndex::Color(ColorRevIndex { db })
+    }
+
+    /// Resolve (or create) the color that covers `current_color`'s datasets
+    /// plus `new_idx`, deduplicating identical dataset sets under one color.
+    fn update_color(&self, current_color: Option<Color>, new_idx: DatasetID) -> Color {
+        let cf_colors = self.db.cf_handle(COLORS).unwrap();
+
+        let mut idxs = match current_color {
+            Some(color) => {
+                let raw = self
+                    .db
+                    .get_cf(&cf_colors, be_bytes(color))
+                    .expect("error reading color")
+                    .expect("current_color must exist in order to be updated");
+                Datasets::from_slice(&raw).unwrap()
+            }
+            None => Datasets::default(),
+        };
+
+        if idxs.contains(&new_idx) {
+            return current_color.unwrap();
+        }
+        idxs.extend([new_idx]);
+
+        let new_color = Self::compute_color(&idxs);
+        if self
+            .db
+            .get_cf(&cf_colors, be_bytes(new_color))
+            .expect("error reading color")
+            .is_none()
+        {
+            self.db
+                .put_cf(&cf_colors, be_bytes(new_color), idxs.as_bytes().unwrap())
+                .expect("error writing color");
+        }
+        new_color
+    }
+
+    fn compute_color(idxs: &Datasets) -> Color {
+        use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
+
+        let s = BuildHasherDefault::<twox_hash::Xxh3Hash128>::default();
+        let mut hasher = s.build_hasher();
+        let mut sorted: Vec<DatasetID> = idxs.clone().into_iter().collect();
+        sorted.sort_unstable();
+        sorted.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Repoint every hash in `search_mh` at the color that now also covers
+    /// `dataset_id`. The read-modify-write against HASHES/COLORS is not
+    /// associative like the plain merge operator, so concurrent indexing of
+    /// datasets that share a hash must serialize here, otherwise one
+    /// thread's `put_cf` can silently clobber another's color update.
+    fn map_hashes_colors(&self, lock: &Mutex<()>, dataset_id: DatasetID, search_mh: &KmerMinHash) {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+
+        for hash in search_mh.mins() {
+            let _guard = lock.lock().unwrap();
+
+            let current = self
+                .db
+                .get_cf(&cf_hashes, be_bytes(hash))
+                .expect("error reading hash")
+                .map(|b| from_be_bytes(&b));
+
+            let new_color = self.update_color(current, dataset_id);
+
+            self.db
+                .put_cf(&cf_hashes, be_bytes(hash), be_bytes(new_color))
+                .expect("error writing hash color");
+        }
+    }
+
+    /// Rebuild the hash bloom filter from the full HASHES CF, sized from the
+    /// actual number of indexed hashes. Rebuilding from the whole CF (rather
+    /// than just the hashes touched by this call) keeps incremental `index()`
+    /// calls from persisting a filter that only covers the latest batch and
+    /// silently treats earlier-batch hashes as definitely absent.
+    fn rebuild_bloom(&self) {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let cf_bloom = self.db.cf_handle(HASHES_BLOOM).unwrap();
+
+        let num_hashes = self
+            .db
+            .iterator_cf(&cf_hashes, rocksdb::IteratorMode::Start)
+            .count() as u64;
+
+        let mut bloom = Bloom::new(num_hashes, BLOOM_FP_RATE);
+        for (key, _) in self
+            .db
+            .iterator_cf(&cf_hashes, rocksdb::IteratorMode::Start)
+            .flatten()
+        {
+            bloom.insert(from_be_bytes(&key));
+        }
+
+        self.db
+            .put_cf(&cf_bloom, BLOOM_KEY, bloom.as_bytes().unwrap())
+            .expect("error persisting bloom filter");
+    }
+
+    pub fn index(
+        &self,
+        index_sigs: Vec<PathBuf>,
+        template: &Sketch,
+        threshold: f64,
+        save_paths: bool,
+        picklist: Option<&Picklist>,
+    ) {
+        let cf_manifest = self.db.cf_handle(MANIFEST).unwrap();
+        let processed_sigs = AtomicUsize::new(0);
+        // Color updates are a non-associative read-modify-write, unlike the
+        // plain index's merge operator, so they're serialized per call.
+        let color_lock = Mutex::new(());
+
+        index_sigs.par_iter().enumerate().for_each(|(dataset_id, filename)| {
+            let dataset_id = dataset_id as DatasetID;
+            let i = processed_sigs.fetch_add(1, Ordering::SeqCst);
+            if i % 1000 == 0 {
+                info!("Processed {} reference sigs", i);
+            }
+
+            let search_sig = Signature::from_path(filename)
+                .unwrap_or_else(|_| panic!("Error processing {:?}", filename))
+                .swap_remove(0);
+
+            let mut search_mh = None;
+            if let Some(Sketch::MinHash(mh)) = search_sig.select_sketch(template) {
+                search_mh = Some(mh.clone());
+            }
+            let search_mh = search_mh.expect("Couldn't find a compatible MinHash");
+            let size = search_mh.size() as u64;
+
+            let row = ManifestRow {
+                md5: search_sig.md5sum(),
+                ksize: search_mh.ksize() as u32,
+                moltype: "DNA".to_string(),
+                scaled: search_mh.scaled(),
+                num: search_mh.num(),
+                filename: filename.to_str().unwrap().to_string(),
+                name: search_sig.name(),
+            };
+            if !picklist.map_or(true, |p| p.matches(&row)) {
+                return;
+            }
+            self.db
+                .put_cf(&cf_manifest, be_bytes(dataset_id), row.as_bytes().unwrap())
+                .expect("error writing manifest row");
+
+            self.map_hashes_colors(&color_lock, dataset_id, &search_mh);
+            sig_save_to_db(
+                self.db.clone(),
+                search_sig,
+                search_mh,
+                size,
+                threshold,
+                save_paths,
+                filename,
+                dataset_id,
+            );
+        });
+
+        self.rebuild_bloom();
+
+        info!("Processed {} reference sigs", processed_sigs.into_inner());
+    }
+
+    /// Load the persisted hash bloom filter, if the index has one.
+    fn load_bloom(&self) -> Option<Bloom> {
+        let cf_bloom = self.db.cf_handle(HASHES_BLOOM)?;
+        self.db
+            .get_cf(&cf_bloom, BLOOM_KEY)
+            .ok()
+            .flatten()
+            .and_then(|b| Bloom::from_slice(&b))
+    }
+
+    pub fn counter_for_query(&self, query: &KmerMinHash) -> SigCounter {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let cf_colors = self.db.cf_handle(COLORS).unwrap();
+
+        // Skip the RocksDB round-trip entirely for hashes the bloom filter
+        // says are definitely absent from this index.
+        let bloom = self.load_bloom();
+        let present_hashes: Vec<u64> = query
+            .iter_mins()
+            .filter(|&hash| bloom.as_ref().map_or(true, |b| b.contains(hash)))
+            .collect();
+
+        info!("Resolving hash colors");
+        let color_keys = present_hashes.iter().map(|&hash| (&cf_hashes, be_bytes(hash)));
+        let colors_per_hash: Vec<Option<Color>> = self
+            .db
+            .multi_get_cf(color_keys)
+            .into_iter()
+            .map(|r| r.ok().flatten().map(|b| from_be_bytes(&b)))
+            .collect();
+
+        info!("Expanding colors to datasets");
+        let unique: HashSet<Color> = colors_per_hash.iter().flatten().copied().collect();
+        let ds_keys = unique.iter().map(|&c| (&cf_colors, be_bytes(c)));
+        let datasets: std::collections::HashMap<Color, Datasets> = unique
+            .iter()
+            .copied()
+            .zip(self.db.multi_get_cf(ds_keys))
+            .filter_map(|(color, raw)| {
+                raw.ok()
+                    .flatten()
+                    .and_then(|b| Datasets::from_slice(&b))
+                    .map(|ds| (color, ds))
+            })
+            .collect();
+
+        colors_per_hash
+            .into_iter()
+            .flatten()
+            .filter_map(|color| datasets.get(&color).cloned())
+            .flat_map(|ds| ds.into_iter())
+            .collect()
+    }
+
+    pub fn matches_from_counter(self, counter: SigCounter, threshold: usize) -> Vec<String> {
+        let cf_sigs = self.db.cf_handle(SIGS).unwrap();
+
+        let ids: Vec<DatasetID> = counter
+            .most_common()
+            .into_iter()
+            .filter(|(_, size)| *size >= threshold)
+            .map(|(id, _)| id)
+            .collect();
+
+        let keys = ids.iter().map(|&id| (&cf_sigs, be_bytes(id)));
+        self.db
+            .multi_get_cf(keys)
+            .into_iter()
+            .filter_map(|raw| raw.ok().flatten())
+            .filter_map(|raw| SignatureData::from_slice(&raw))
+            .filter_map(|sig| match sig {
+                SignatureData::External(path) => Some(path),
+                SignatureData::Internal(sig) => Some(sig.name()),
+                SignatureData::Empty => None,
+            })
+            .collect()
+    }
+
+    pub fn prepare_gather_counters(
+        &self,
+        query: &KmerMinHash,
+    ) -> (SigCounter, QueryColors, HashToColor) {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let cf_colors = self.db.cf_handle(COLORS).unwrap();
+
+        let bloom = self.load_bloom();
+        let query_hashes: Vec<u64> = query
+            .mins()
+            .into_iter()
+            .filter(|&hash| bloom.as_ref().map_or(true, |b| b.contains(hash)))
+            .collect();
+        let color_keys = query_hashes.iter().map(|&hash| (&cf_hashes, be_bytes(hash)));
+        let hash_to_color: HashToColor = query_hashes
+            .iter()
+            .copied()
+            .zip(self.db.multi_get_cf(color_keys))
+            .filter_map(|(hash, raw)| raw.ok().flatten().map(|b| (hash, from_be_bytes(&b))))
+            .collect();
+
+        let unique: HashSet<Color> = hash_to_color.values().copied().collect();
+        let ds_keys = unique.iter().map(|&c| (&cf_colors, be_bytes(c)));
+        let query_colors: QueryColors = unique
+            .iter()
+            .copied()
+            .zip(self.db.multi_get_cf(ds_keys))
+            .filter_map(|(color, raw)| {
+                raw.ok()
+                    .flatten()
+                    .and_then(|b| Datasets::from_slice(&b))
+                    .map(|ds| (color, ds))
+            })
+            .collect();
+
+        // Each query hash contributes once, with the multiplicity of hashes
+        // pointing at the same color, matching `counter_for_query`.
+        let counter: SigCounter = hash_to_color
+            .values()
+            .filter_map(|color| query_colors.get(color))
+            .flat_map(|ds| ds.clone().into_iter())
+            .collect();
+
+        (counter, query_colors, hash_to_color)
+    }
+
+    pub fn gather(
+        &self,
+        mut counter: SigCounter,
+        query_colors: QueryColors,
+        hash_to_color: HashToColor,
+        threshold: usize,
+        query: &KmerMinHash,
+        template: &Sketch,
+    ) -> Result<Vec<GatherResult>, Box<dyn std::error::Error>> {
+        let cf_sigs = self.db.cf_handle(SIGS).unwrap();
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let cf_colors = self.db.cf_handle(COLORS).unwrap();
+
+        let scaled = std::cmp::max(query.scaled() as usize, 1);
+        let mut remaining: HashSet<u64> = query.mins().into_iter().collect();
+        // Colors already resolved while preparing the query carry over here;
+        // any color first touched while decrementing gets cached too, so a
+        // color repeated across iterations costs at most one RocksDB read.
+        let mut color_cache = query_colors;
+
+        let mut matches = Vec::new();
+        while let Some((dataset_id, &size)) = counter.most_common().first().map(|(d, c)| (*d, c)) {
+            if size < threshold || remaining.is_empty() {
+                break;
+            }
+
+            let raw = self
+                .db
+                .get_cf(&cf_sigs, be_bytes(dataset_id))?
+                .expect("dataset must have a signature entry");
+            let match_name = match SignatureData::from_slice(&raw) {
+                Some(SignatureData::External(path)) => path,
+                Some(SignatureData::Internal(ref sig)) => sig.name(),
+                _ => panic!("dataset {} has no usable signature", dataset_id),
+            };
+
+            let match_mh = match SignatureData::from_slice(&raw) {
+                Some(SignatureData::Internal(sig)) => sig
+                    .select_sketch(template)
+                    .and_then(|s| match s {
+                        Sketch::MinHash(mh) => Some(mh),
+                        _ => None,
+                    })
+                    .expect("Couldn't find a compatible MinHash in match"),
+                _ => {
+                    let match_sig = Signature::from_path(&match_name)?.swap_remove(0);
+                    match_sig
+                        .select_sketch(template)
+                        .and_then(|s| match s {
+                            Sketch::MinHash(mh) => Some(mh.clone()),
+                            _ => None,
+                        })
+                        .expect("Couldn't find a compatible MinHash in match")
+                }
+            };
+
+            let intersection: Vec<u64> = match_mh
+                .mins()
+                .into_iter()
+                .filter(|h| remaining.contains(h))
+                .collect();
+
+            matches.push(GatherResult {
+                match_name,
+                intersect_bp: intersection.len() * scaled,
+                f_match: intersection.len() as f64 / std::cmp::max(match_mh.size(), 1) as f64,
+                remaining_bp: remaining.len() * scaled,
+            });
+
+            // Resolve the color for each removed hash (falling back to a
+            // fresh lookup for hashes `hash_to_color` doesn't map) and
+            // decrement every other dataset it covers.
+            for hash in &intersection {
+                let color = match hash_to_color.get(hash) {
+                    Some(&c) => Some(c),
+                    None => self
+                        .db
+                        .get_cf(&cf_hashes, be_bytes(*hash))?
+                        .map(|b| from_be_bytes(&b)),
+                };
+                let Some(color) = color else { continue };
+
+                let datasets = match color_cache.get(&color) {
+                    Some(ds) => ds.clone(),
+                    None => {
+                        let raw = self.db.get_cf(&cf_colors, be_bytes(color))?;
+                        let ds = raw
+                            .and_then(|b| Datasets::from_slice(&b))
+                            .unwrap_or_default();
+                        color_cache.insert(color, ds.clone());
+                        ds
+                    }
+                };
+
+                for other in datasets.into_iter() {
+                    if let Some(c) = counter.get_mut(&other) {
+                        *c = c.saturating_sub(1);
+                    }
+                }
+            }
+
+            for hash in &intersection {
+                remaining.remove(hash);
+            }
+            counter.remove(&dataset_id);
+        }
+
+        Ok(matches)
+    }
+
+    pub fn compact(&self) {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let cf_colors = self.db.cf_handle(COLORS).unwrap();
+        self.db.compact_range_cf(&cf_hashes, None::<&[u8]>, None::<&[u8]>);
+        self.db.compact_range_cf(&cf_colors, None::<&[u8]>, None::<&[u8]>);
+    }
+
+    pub fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Tally hashes referencing each color and, unless `quick` is set, delete
+    /// colors nothing references any more (with `quick` they are only counted).
+    pub fn check(&self, quick: bool) {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let cf_colors = self.db.cf_handle(COLORS).unwrap();
+
+        let mut references: std::collections::HashMap<Color, usize> = Default::default();
+        for (_, value) in self.db.iterator_cf(&cf_hashes, rocksdb::IteratorMode::Start) {
+            *references.entry(from_be_bytes(&value)).or_default() += 1;
+        }
+
+        let mut colors = 0;
+        let mut reclaimed = 0;
+        for (key, _) in self.db.iterator_cf(&cf_colors, rocksdb::IteratorMode::Start) {
+            colors += 1;
+            let color = from_be_bytes(&key);
+            if !references.contains_key(&color) {
+                if !quick {
+                    self.db
+                        .delete_cf(&cf_colors, &key)
+                        .expect("error deleting unreferenced color");
+                }
+                reclaimed += 1;
+            }
+        }
+
+        info!("hashes: {}, colors: {}", references.len(), colors);
+        if quick {
+            info!("found {} unreferenced colors", reclaimed);
+        } else {
+            info!("reclaimed {} unreferenced colors", reclaimed);
+        }
+    }
+
+    pub fn checkpoint(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let checkpoint = rocksdb::checkpoint::Checkpoint::new(&*self.db)?;
+        checkpoint.create_checkpoint(path)?;
+        Ok(())
+    }
+
+    pub fn backup(&self, engine_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        use rocksdb::backup::{BackupEngine, BackupEngineOptions};
+
+        let env = rocksdb::Env::new()?;
+        let be_opts = BackupEngineOptions::new(engine_path)?;
+        let mut engine = BackupEngine::open(&be_opts, &env)?;
+        engine.create_new_backup(&*self.db)?;
+        Ok(())
+    }
+
+    /// Iterate the hashes present in the HASHES CF whose values fall within
+    /// `[min_hash, max_hash]`, relying on the big-endian key encoding so a
+    /// forward seek from `min_hash` yields them in ascending order.
+    pub fn hashes_in_range(&self, min_hash: u64, max_hash: u64) -> Vec<u64> {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let start = be_bytes(min_hash);
+        let iter = self.db.iterator_cf(
+            &cf_hashes,
+            rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward),
+        );
+
+        let mut out = Vec::new();
+        for (key, _) in iter {
+            let hash = from_be_bytes(&key);
+            if hash > max_hash {
+                break;
+            }
+            out.push(hash);
+        }
+        out
+    }
+
+    /// Iterate the dataset IDs stored in the SIGS CF within `[lo, hi]`.
+    pub fn datasets_in_range(&self, lo: DatasetID, hi: DatasetID) -> Vec<DatasetID> {
+        let cf_sigs = self.db.cf_handle(SIGS).unwrap();
+        let start = be_bytes(lo);
+        let iter = self.db.iterator_cf(
+            &cf_sigs,
+            rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward),
+        );
+
+        let mut out = Vec::new();
+        for (key, _) in iter {
+            let id = from_be_bytes(&key);
+            if id > hi {
+                break;
+            }
+            out.push(id);
+        }
+        out
+    }
+}
@@ -1,4 +1,6 @@
+pub mod bloom;
 pub mod color_revindex;
+pub mod manifest;
 pub mod revindex;
 
 use std::collections::{BTreeSet, HashMap};
@@ -7,12 +9,12 @@ use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use histogram::Histogram;
 use log::info;
 use rkyv::{Archive, Deserialize, Serialize};
+use roaring::RoaringTreemap;
 
-use sourmash::index::revindex::GatherResult;
 use sourmash::signature::{Signature, SigsTrait};
 use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
 use sourmash::sketch::Sketch;
@@ -31,6 +33,23 @@ pub const HASHES: &str = "hashes";
 pub const SIGS: &str = "signatures";
 pub const COLORS: &str = "colors";
 
+/// Encode `v` big-endian so RocksDB's bytewise comparator orders keys
+/// numerically, keeping range/prefix scans over these CFs meaningful.
+pub(crate) fn be_bytes(v: u64) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    (&mut bytes[..])
+        .write_u64::<BigEndian>(v)
+        .expect("error writing bytes");
+    bytes
+}
+
+/// Decode a big-endian `u64` key written by [`be_bytes`].
+pub(crate) fn from_be_bytes(bytes: &[u8]) -> u64 {
+    (&bytes[..8])
+        .read_u64::<BigEndian>()
+        .expect("error reading bytes")
+}
+
 pub enum RevIndex {
     Color(color_revindex::ColorRevIndex),
     Plain(revindex::RevIndex),
@@ -56,7 +75,7 @@ impl RevIndex {
         query: &KmerMinHash,
     ) -> (SigCounter, QueryColors, HashToColor) {
         match self {
-            Self::Color(_db) => todo!(), //db.prepare_gather_counters(query),
+            Self::Color(db) => db.prepare_gather_counters(query),
             Self::Plain(db) => db.prepare_gather_counters(query),
         }
     }
@@ -67,10 +86,11 @@ impl RevIndex {
         template: &Sketch,
         threshold: f64,
         save_paths: bool,
+        picklist: Option<&manifest::Picklist>,
     ) {
         match self {
-            Self::Color(db) => db.index(index_sigs, template, threshold, save_paths),
-            Self::Plain(db) => db.index(index_sigs, template, threshold, save_paths),
+            Self::Color(db) => db.index(index_sigs, template, threshold, save_paths, picklist),
+            Self::Plain(db) => db.index(index_sigs, template, threshold, save_paths, picklist),
         }
     }
 
@@ -94,6 +114,45 @@ impl RevIndex {
         }
     }
 
+    /// Create a consistent, hard-linked point-in-time copy of every column
+    /// family at `path` using RocksDB's Checkpoint API. Pending writes are
+    /// flushed first; RocksDB does not support flushing a read-only handle,
+    /// so on one this returns that flush error and creates no checkpoint.
+    pub fn checkpoint(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.flush()?;
+        match self {
+            Self::Color(db) => db.checkpoint(path),
+            Self::Plain(db) => db.checkpoint(path),
+        }
+    }
+
+    /// Append an incremental backup of the database to the BackupEngine rooted
+    /// at `engine_path`.
+    pub fn backup(&self, engine_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.flush()?;
+        match self {
+            Self::Color(db) => db.backup(engine_path),
+            Self::Plain(db) => db.backup(engine_path),
+        }
+    }
+
+    /// Rebuild a working database at `db_path` from the latest backup stored in
+    /// the BackupEngine at `engine_path`.
+    pub fn restore_from_backup(
+        engine_path: &Path,
+        db_path: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use rocksdb::backup::{BackupEngine, BackupEngineOptions, RestoreOptions};
+
+        let env = rocksdb::Env::new()?;
+        let opts = BackupEngineOptions::new(engine_path)?;
+        let mut engine = BackupEngine::open(&opts, &env)?;
+        let mut restore_opts = RestoreOptions::default();
+        restore_opts.set_keep_log_files(false);
+        engine.restore_from_latest_backup(db_path, db_path, &restore_opts)?;
+        Ok(())
+    }
+
     pub fn open(index: &Path, read_only: bool, colors: bool) -> Self {
         if colors {
             color_revindex::ColorRevIndex::open(index, read_only)
@@ -102,6 +161,24 @@ impl RevIndex {
         }
     }
 
+    /// Iterate the hashes present in the HASHES CF whose values fall within
+    /// `[min_hash, max_hash]`, relying on the big-endian key encoding so a
+    /// forward seek from `min_hash` yields them in ascending order.
+    pub fn hashes_in_range(&self, min_hash: u64, max_hash: u64) -> Vec<u64> {
+        match self {
+            Self::Color(db) => db.hashes_in_range(min_hash, max_hash),
+            Self::Plain(db) => db.hashes_in_range(min_hash, max_hash),
+        }
+    }
+
+    /// Iterate the dataset IDs stored in the SIGS CF within `[lo, hi]`.
+    pub fn datasets_in_range(&self, lo: DatasetID, hi: DatasetID) -> Vec<DatasetID> {
+        match self {
+            Self::Color(db) => db.datasets_in_range(lo, hi),
+            Self::Plain(db) => db.datasets_in_range(lo, hi),
+        }
+    }
+
     pub fn gather(
         &self,
         counter: SigCounter,
@@ -112,7 +189,14 @@ impl RevIndex {
         template: &Sketch,
     ) -> Result<Vec<GatherResult>, Box<dyn std::error::Error>> {
         match self {
-            Self::Color(_db) => todo!(),
+            Self::Color(db) => db.gather(
+                counter,
+                query_colors,
+                hash_to_color,
+                threshold,
+                query,
+                template,
+            ),
             Self::Plain(db) => db.gather(
                 counter,
                 query_colors,
@@ -125,6 +209,15 @@ impl RevIndex {
     }
 }
 
+/// One non-redundant match emitted by [`RevIndex::gather`].
+#[derive(Debug)]
+pub struct GatherResult {
+    pub match_name: String,
+    pub intersect_bp: usize,
+    pub f_match: f64,
+    pub remaining_bp: usize,
+}
+
 #[derive(Debug, PartialEq, Clone, Archive, Serialize, Deserialize)]
 pub enum SignatureData {
     Empty,
@@ -229,11 +322,83 @@ pub fn read_paths<P: AsRef<Path>>(
         .collect())
 }
 
-#[derive(Debug, PartialEq, Clone, Archive, Serialize, Deserialize, Hash)]
+/// Load signature paths to index, either from a newline-delimited path list
+/// or (when `siglist` ends in `.csv`) from a sourmash-style manifest,
+/// optionally restricted by `picklist`.
+pub fn load_sigpaths<P: AsRef<Path>>(
+    siglist: P,
+    picklist: Option<&manifest::Picklist>,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let is_csv = siglist
+        .as_ref()
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("csv"));
+
+    if !is_csv {
+        return read_paths(siglist);
+    }
+
+    Ok(manifest::Manifest::from_csv(siglist)?.paths(picklist))
+}
+
+/// Number of datasets in a `Many` set above which it is promoted to a
+/// Roaring bitmap. Below this a `BTreeSet` is cheaper to carry around; above
+/// it Roaring's partitioned containers win on both size and union speed.
+const ROARING_THRESHOLD: usize = 4096;
+
+/// Archives a `RoaringTreemap` as its portable serialization bytes, so the
+/// `Roaring` variant can hold a live bitmap in memory -- cheap to extend,
+/// union, and query -- while still round-tripping through the rkyv envelope
+/// as a plain byte blob on disk.
+struct RoaringAsBytes;
+
+impl rkyv::with::ArchiveWith<RoaringTreemap> for RoaringAsBytes {
+    type Archived = <Vec<u8> as Archive>::Archived;
+    type Resolver = <Vec<u8> as Archive>::Resolver;
+
+    unsafe fn resolve_with(
+        map: &RoaringTreemap,
+        pos: usize,
+        resolver: Self::Resolver,
+        out: *mut Self::Archived,
+    ) {
+        Datasets::encode_roaring(map).resolve(pos, resolver, out);
+    }
+}
+
+impl<S: rkyv::Fallible + ?Sized> rkyv::with::SerializeWith<RoaringTreemap, S> for RoaringAsBytes
+where
+    Vec<u8>: rkyv::Serialize<S>,
+{
+    fn serialize_with(map: &RoaringTreemap, serializer: &mut S) -> Result<Self::Resolver, S::Error> {
+        Datasets::encode_roaring(map).serialize(serializer)
+    }
+}
+
+impl<D: rkyv::Fallible + ?Sized> rkyv::with::DeserializeWith<<Vec<u8> as Archive>::Archived, RoaringTreemap, D>
+    for RoaringAsBytes
+where
+    <Vec<u8> as Archive>::Archived: rkyv::Deserialize<Vec<u8>, D>,
+{
+    fn deserialize_with(
+        bytes: &<Vec<u8> as Archive>::Archived,
+        deserializer: &mut D,
+    ) -> Result<RoaringTreemap, D::Error> {
+        let bytes: Vec<u8> = bytes.deserialize(deserializer)?;
+        Ok(Datasets::decode_roaring(&bytes))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Archive, Serialize, Deserialize)]
 pub enum Datasets {
     Empty,
     Unique(DatasetID),
     Many(BTreeSet<DatasetID>),
+    /// A Roaring bitmap of dataset IDs, kept live in memory (via
+    /// `RoaringAsBytes`) so repeated inserts/unions/lookups don't each pay to
+    /// decode and re-encode the whole bitmap. Used for hashes shared by more
+    /// than `ROARING_THRESHOLD` datasets.
+    Roaring(#[with(RoaringAsBytes)] RoaringTreemap),
 }
 
 impl IntoIterator for Datasets {
@@ -245,6 +410,7 @@ impl IntoIterator for Datasets {
             Self::Empty => Box::new(std::iter::empty()),
             Self::Unique(v) => Box::new(std::iter::once(v)),
             Self::Many(v) => Box::new(v.into_iter()),
+            Self::Roaring(map) => Box::new(map.into_iter()),
         }
     }
 }
@@ -270,6 +436,14 @@ impl Extend<DatasetID> for Datasets {
                 }
                 Self::Many(v) => {
                     v.insert(value);
+                    if v.len() > ROARING_THRESHOLD {
+                        let mut map = RoaringTreemap::new();
+                        map.extend(v.iter().copied());
+                        *self = Datasets::Roaring(map);
+                    }
+                }
+                Self::Roaring(map) => {
+                    map.insert(value);
                 }
             }
         }
@@ -282,11 +456,28 @@ impl Datasets {
             Self::Empty
         } else if vals.len() == 1 {
             Self::Unique(vals[0])
+        } else if vals.len() > ROARING_THRESHOLD {
+            let mut map = RoaringTreemap::new();
+            map.extend(vals.iter().copied());
+            Self::Roaring(map)
         } else {
             Self::Many(BTreeSet::from_iter(vals.iter().cloned()))
         }
     }
 
+    /// Decode a Roaring bitmap from its portable serialization bytes.
+    fn decode_roaring(bytes: &[u8]) -> RoaringTreemap {
+        RoaringTreemap::deserialize_from(bytes).expect("error decoding roaring bitmap")
+    }
+
+    /// Encode a Roaring bitmap into its portable serialization bytes.
+    fn encode_roaring(map: &RoaringTreemap) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(map.serialized_size());
+        map.serialize_into(&mut buf)
+            .expect("error encoding roaring bitmap");
+        buf
+    }
+
     pub fn from_slice(slice: &[u8]) -> Option<Self> {
         // TODO: avoid the aligned vec allocation here
         let mut vec = rkyv::AlignedVec::new();
@@ -311,10 +502,19 @@ impl Datasets {
     }
 
     pub fn union(&mut self, other: Datasets) {
+        // Once either side is Roaring the cheapest merge is on Roaring itself,
+        // so fold both sides into a bitmap.
+        if matches!(self, Datasets::Roaring(_)) || matches!(other, Datasets::Roaring(_)) {
+            let mut map = self.to_roaring();
+            map |= other.to_roaring();
+            *self = Datasets::Roaring(map);
+            return;
+        }
         match self {
             Datasets::Empty => match other {
                 Datasets::Empty => (),
                 Datasets::Unique(_) | Datasets::Many(_) => *self = other,
+                Datasets::Roaring(_) => unreachable!(),
             },
             Datasets::Unique(v) => match other {
                 Datasets::Empty => (),
@@ -328,8 +528,28 @@ impl Datasets {
                     new_hashset.extend(o.into_iter());
                     *self = Datasets::Many(new_hashset);
                 }
+                Datasets::Roaring(_) => unreachable!(),
             },
-            Datasets::Many(ref mut v) => v.extend(other.into_iter()),
+            Datasets::Many(ref mut v) => {
+                v.extend(other.into_iter());
+                if v.len() > ROARING_THRESHOLD {
+                    let mut map = RoaringTreemap::new();
+                    map.extend(v.iter().copied());
+                    *self = Datasets::Roaring(map);
+                }
+            }
+            Datasets::Roaring(_) => unreachable!(),
+        }
+    }
+
+    /// Materialize this set as a Roaring bitmap, regardless of its current
+    /// representation.
+    fn to_roaring(&self) -> RoaringTreemap {
+        match self {
+            Self::Empty => RoaringTreemap::new(),
+            Self::Unique(v) => RoaringTreemap::from_iter([*v]),
+            Self::Many(v) => RoaringTreemap::from_iter(v.iter().copied()),
+            Self::Roaring(map) => map.clone(),
         }
     }
 
@@ -338,6 +558,7 @@ impl Datasets {
             Self::Empty => 0,
             Self::Unique(_) => 1,
             Self::Many(ref v) => v.len(),
+            Self::Roaring(map) => map.len() as usize,
         }
     }
 
@@ -350,6 +571,7 @@ impl Datasets {
             Self::Empty => false,
             Self::Unique(v) => v == value,
             Self::Many(ref v) => v.contains(value),
+            Self::Roaring(map) => map.contains(*value),
         }
     }
 }
@@ -377,9 +599,11 @@ pub fn sig_save_to_db(
 
     let sig_bytes = sig.as_bytes().unwrap();
     let cf_sigs = db.cf_handle(SIGS).unwrap();
+    // Big-endian so RocksDB's bytewise comparator orders keys numerically,
+    // which keeps range and prefix scans over the SIGS CF meaningful.
     let mut hash_bytes = [0u8; 8];
     (&mut hash_bytes[..])
-        .write_u64::<LittleEndian>(dataset_id)
+        .write_u64::<BigEndian>(dataset_id)
         .expect("error writing bytes");
     db.put_cf(&cf_sigs, &hash_bytes[..], sig_bytes.as_slice())
         .expect("error saving sig");
@@ -391,6 +615,16 @@ pub fn stats_for_cf(db: Arc<DB>, cf_name: &str, deep_check: bool, quick: bool) {
 
     let cf = db.cf_handle(cf_name).unwrap();
 
+    if cf_name == crate::bloom::HASHES_BLOOM {
+        if let Some(raw) = db.get_cf(&cf, b"bloom").expect("error reading bloom") {
+            let filter = crate::bloom::Bloom::from_slice(&raw).expect("error decoding bloom");
+            info!("*** {} ***", cf_name);
+            info!("occupancy: {:.2}%", filter.occupancy() * 100.0);
+            info!("estimated FP rate: {:.4}%", filter.estimated_fp_rate() * 100.0);
+        }
+        return;
+    }
+
     let iter = db.iterator_cf(&cf, rocksdb::IteratorMode::Start);
     let mut kcount = 0;
     let mut vcount = 0;
@@ -398,7 +632,7 @@ pub fn stats_for_cf(db: Arc<DB>, cf_name: &str, deep_check: bool, quick: bool) {
     let mut datasets: Datasets = Default::default();
 
     for (key, value) in iter {
-        let _k = (&key[..]).read_u64::<LittleEndian>().unwrap();
+        let _k = (&key[..]).read_u64::<BigEndian>().unwrap();
         kcount += key.len();
 
         //println!("Saw {} {:?}", k, Datasets::from_slice(&value));
@@ -1,18 +1,19 @@
 use std::cmp;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
 use std::io::BufRead;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use clap::{Parser, Subcommand};
 use log::info;
 use rayon::prelude::*;
 use rkyv::{Archive, Deserialize, Serialize};
+use roaring::RoaringTreemap;
 use rocksdb::{MergeOperands, Options};
 
 use sourmash::signature::{Signature, SigsTrait};
@@ -27,29 +28,55 @@ type SigCounter = counter::Counter<DatasetID>;
 
 type Color = u64;
 
+/// Encode `v` big-endian so RocksDB's bytewise comparator orders keys
+/// numerically, keeping range/prefix scans over HASHES/COLORS meaningful.
+fn be_bytes(v: u64) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    (&mut bytes[..])
+        .write_u64::<BigEndian>(v)
+        .expect("error writing bytes");
+    bytes
+}
+
+/// Decode a big-endian `u64` key written by [`be_bytes`].
+fn from_be_bytes(bytes: &[u8]) -> u64 {
+    (&bytes[..8])
+        .read_u64::<BigEndian>()
+        .expect("error reading bytes")
+}
+
+/// Column family mapping each hash to the `Color` of its dataset set.
+const HASHES: &str = "hashes";
+/// Column family mapping each `Color` to the `Datasets` it encodes.
+const COLORS: &str = "colors";
+/// Column family mapping each `DatasetID` to its signature path, so lookups
+/// don't depend on a sig list being loaded in the same order it was indexed.
+const NAMES: &str = "names";
+
+/// Column families opened for every revindex database.
+fn cf_names() -> Vec<&'static str> {
+    vec![HASHES, COLORS, NAMES]
+}
+
 fn merge_datasets(
     _: &[u8],
     existing_val: Option<&[u8]>,
     operands: &MergeOperands,
 ) -> Option<Vec<u8>> {
-    let original_datasets = existing_val
+    let mut datasets = existing_val
         .and_then(Datasets::from_slice)
         .unwrap_or_default();
-    let mut datasets = original_datasets.clone();
 
     for op in operands {
         let new_vals = Datasets::from_slice(op).unwrap();
-        datasets = Datasets(datasets.0.union(&new_vals.0).cloned().collect());
+        datasets.union(new_vals);
     }
-    //    if let Some(_) = datasets.0.difference(&original_datasets.0).next() {
     datasets.as_bytes()
-    //    } else {
-    //        None
-    //    }
 }
 
 fn map_hashes_colors(
     db: Arc<DB>,
+    lock: &Mutex<()>,
     dataset_id: DatasetID,
     search_sig: &Signature,
     threshold: f64,
@@ -62,64 +89,81 @@ fn map_hashes_colors(
     }
 
     let search_mh = search_mh.expect("Couldn't find a compatible MinHash");
-    let colors = Datasets::new(&[dataset_id]).as_bytes().unwrap();
+    let cf_hashes = db.cf_handle(HASHES).unwrap();
 
     let matched = search_mh.mins();
     let size = matched.len() as u64;
     if !matched.is_empty() || size > threshold as u64 {
         // FIXME threshold is f64
-        let mut hash_bytes = [0u8; 8];
         for hash in matched {
-            (&mut hash_bytes[..])
-                .write_u64::<LittleEndian>(hash)
-                .expect("error writing bytes");
-            db.merge(&hash_bytes[..], colors.as_slice())
-                .expect("error merging");
+            let hash_key = be_bytes(hash);
+
+            // The read-modify-write below isn't associative like the merge
+            // operator installed on this CF, so datasets sharing a hash must
+            // take turns here under par_iter, or one thread's put_cf can
+            // clobber another thread's color update.
+            let _guard = lock.lock().unwrap();
+
+            let current = db
+                .get_cf(&cf_hashes, hash_key)
+                .expect("error reading hash")
+                .map(|b| from_be_bytes(&b));
+
+            // Resolve (or create) the color that covers this dataset, then
+            // repoint the hash at it. Identical dataset sets share one color.
+            let new_color = Colors::update(db.clone(), current, &[dataset_id])
+                .expect("error updating color");
+
+            db.put_cf(&cf_hashes, hash_key, be_bytes(new_color))
+                .expect("error writing hash color");
         }
     }
+}
 
-    /*
-        if hash_to_color.is_empty() {
-            None
-        } else {
-            Some((hash_to_color, colors))
-        }
-    */
+/// Look up the signature path recorded for `dataset_id` in the NAMES CF.
+fn name_for_dataset(db: &DB, dataset_id: DatasetID) -> Option<String> {
+    let cf_names = db.cf_handle(NAMES).unwrap();
+    let raw = db
+        .get_cf(&cf_names, be_bytes(dataset_id))
+        .expect("error reading name")?;
+    Some(String::from_utf8(raw).expect("invalid utf8 in stored name"))
 }
 
 fn counter_for_query(db: Arc<DB>, query: &KmerMinHash) -> SigCounter {
-    info!("Collecting hashes");
-    let hashes_iter = query.iter_mins().map(|hash| {
-        let mut v = vec![0_u8; 8];
-        (&mut v[..])
-            .write_u64::<LittleEndian>(*hash)
-            .expect("error writing bytes");
-        v
-    });
-
-    info!("Multi get");
-    db.multi_get(hashes_iter)
+    let cf_hashes = db.cf_handle(HASHES).unwrap();
+    let cf_colors = db.cf_handle(COLORS).unwrap();
+
+    info!("Resolving hash colors");
+    let color_keys = query.iter_mins().map(|hash| (&cf_hashes, be_bytes(hash)));
+    let colors_per_hash: Vec<Option<Color>> = db
+        .multi_get_cf(color_keys)
         .into_iter()
-        .filter_map(|r| r.ok().unwrap())
-        .flat_map(|raw_datasets| {
-            let new_vals = Datasets::from_slice(&raw_datasets).unwrap();
-            new_vals.0.into_iter()
+        .map(|r| r.ok().flatten().map(|b| from_be_bytes(&b)))
+        .collect();
+
+    info!("Expanding colors to datasets");
+    // Decode each distinct color's dataset set once, then count datasets with
+    // the multiplicity of the query hashes that pointed at each color.
+    let unique: HashSet<Color> = colors_per_hash.iter().flatten().copied().collect();
+    let ds_keys = unique.iter().map(|c| (&cf_colors, be_bytes(*c)));
+    let datasets: HashMap<Color, Datasets> = unique
+        .iter()
+        .copied()
+        .zip(db.multi_get_cf(ds_keys))
+        .filter_map(|(color, raw)| {
+            raw.ok()
+                .flatten()
+                .and_then(|b| Datasets::from_slice(&b))
+                .map(|ds| (color, ds))
         })
-        .collect()
-    /*
-    info!("get");
-    hashes_iter
+        .collect();
+
+    colors_per_hash
         .into_iter()
-        .filter_map(|r| {
-            let datasets = db.get(&r).ok().unwrap();
-            datasets
-        })
-        .flat_map(|raw_datasets| {
-            let new_vals = Datasets::from_slice(&raw_datasets).unwrap();
-            new_vals.0.into_iter()
-        })
+        .flatten()
+        .filter_map(|color| datasets.get(&color).cloned())
+        .flat_map(|ds| ds.into_iter())
         .collect()
-    */
 }
 
 #[derive(Default, Debug, PartialEq, Clone, Archive, Serialize, Deserialize)]
@@ -143,17 +187,16 @@ impl Colors {
         current_color: Option<Color>,
         new_idxs: I,
     ) -> Result<Color, Box<dyn std::error::Error>> {
+        let cf_colors = db.cf_handle(COLORS).unwrap();
+
         if let Some(color) = current_color {
-            let mut color_bytes = [0u8; 8];
-            (&mut color_bytes[..])
-                .write_u64::<LittleEndian>(color)
-                .expect("error writing bytes");
+            let color_bytes = be_bytes(color);
 
-            if let Some(idxs) = db.get(&color_bytes)? {
+            if let Some(idxs) = db.get_cf(&cf_colors, color_bytes)? {
                 let idxs = Datasets::from_slice(&idxs).unwrap();
                 let idx_to_add: Vec<_> = new_idxs
                     .into_iter()
-                    .filter(|new_idx| !idxs.0.contains(new_idx))
+                    .filter(|&&new_idx| !idxs.contains(&new_idx))
                     .collect();
 
                 if idx_to_add.is_empty() {
@@ -161,32 +204,50 @@ impl Colors {
                     Ok(color)
                 } else {
                     // We need to either create a new color,
-                    // or find an existing color that have the same idxs
+                    // or find an existing color that has the same idxs
 
                     let mut idxs = idxs.clone();
-                    idxs.0.extend(idx_to_add.into_iter().cloned());
+                    idxs.extend(idx_to_add.into_iter().cloned());
                     let new_color = Colors::compute_color(&idxs);
 
-                    // FIXME db.entry(new_color).or_insert_with(|| idxs);
+                    Colors::write_color_if_absent(db.clone(), new_color, &idxs)?;
                     Ok(new_color)
                 }
             } else {
-                unimplemented!("throw error, current_color must exist in order to be updated. current_color: {:?}", current_color);
+                Err(format!(
+                    "current_color must exist in order to be updated. current_color: {:?}",
+                    current_color
+                )
+                .into())
             }
         } else {
             let mut idxs = Datasets::default();
-            idxs.0.extend(new_idxs.into_iter().cloned());
+            idxs.extend(new_idxs.into_iter().cloned());
             let new_color = Colors::compute_color(&idxs);
-            // FIXME db.entry(new_color).or_insert_with(|| idxs);
+            Colors::write_color_if_absent(db.clone(), new_color, &idxs)?;
             Ok(new_color)
         }
     }
 
+    /// Insert `idxs` under `color` in the COLORS CF unless it is already there.
+    fn write_color_if_absent(
+        db: Arc<DB>,
+        color: Color,
+        idxs: &Datasets,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let cf_colors = db.cf_handle(COLORS).unwrap();
+        let color_bytes = be_bytes(color);
+        if db.get_cf(&cf_colors, color_bytes)?.is_none() {
+            db.put_cf(&cf_colors, color_bytes, idxs.as_bytes().unwrap())?;
+        }
+        Ok(())
+    }
+
     fn compute_color(idxs: &Datasets) -> Color {
         let s = BuildHasherDefault::<twox_hash::Xxh3Hash128>::default();
         let mut hasher = s.build_hasher();
         // TODO: remove this...
-        let mut sorted: Vec<_> = idxs.0.iter().collect();
+        let mut sorted: Vec<DatasetID> = idxs.clone().into_iter().collect();
         sorted.sort();
         sorted.hash(&mut hasher);
         hasher.finish()
@@ -225,35 +286,119 @@ impl Colors {
     */
 }
 
-#[derive(Default, Debug, PartialEq, Clone, Archive, Serialize, Deserialize)]
-struct Datasets(HashSet<DatasetID>);
+/// Number of dataset IDs a `Datasets` keeps inline before promoting to a
+/// Roaring bitmap. Singleton and near-singleton hashes stay tiny this way.
+const DATASETS_SMALL_MAX: usize = 4;
+
+/// Tag bytes prefixing the on-disk encoding.
+const TAG_SMALL: u8 = 0;
+const TAG_ROARING: u8 = 1;
+
+/// A set of dataset IDs, stored inline while small and as a Roaring bitmap
+/// once it grows past `DATASETS_SMALL_MAX`.
+///
+/// `DatasetID` is `u64`, so this uses `RoaringTreemap` (64-bit IDs) rather
+/// than `RoaringBitmap` (32-bit IDs), which would silently truncate any
+/// dataset index at or above 2^32 and collide two IDs sharing the low bits.
+#[derive(Debug, PartialEq, Clone)]
+enum Datasets {
+    Small(Vec<DatasetID>),
+    Roaring(RoaringTreemap),
+}
+
+impl Default for Datasets {
+    fn default() -> Self {
+        Datasets::Small(Vec::new())
+    }
+}
 
 impl Datasets {
     fn new(vals: &[DatasetID]) -> Self {
-        Self(HashSet::from_iter(vals.into_iter().cloned()))
+        let mut ds = Datasets::default();
+        ds.extend(vals.iter().copied());
+        ds
+    }
+
+    fn contains(&self, value: &DatasetID) -> bool {
+        match self {
+            Datasets::Small(v) => v.contains(value),
+            Datasets::Roaring(r) => r.contains(*value),
+        }
+    }
+
+    fn extend<I: IntoIterator<Item = DatasetID>>(&mut self, iter: I) {
+        for value in iter {
+            match self {
+                Datasets::Small(v) => {
+                    if !v.contains(&value) {
+                        v.push(value);
+                        if v.len() > DATASETS_SMALL_MAX {
+                            let mut r = RoaringTreemap::new();
+                            r.extend(v.iter().copied());
+                            *self = Datasets::Roaring(r);
+                        }
+                    }
+                }
+                Datasets::Roaring(r) => {
+                    r.insert(value);
+                }
+            }
+        }
+    }
+
+    /// In-place union, used by the merge operator to fold operands together.
+    fn union(&mut self, other: Datasets) {
+        match (&mut *self, other) {
+            (Datasets::Roaring(a), Datasets::Roaring(b)) => *a |= b,
+            (_, other) => self.extend(other),
+        }
     }
 
     fn from_slice(slice: &[u8]) -> Option<Self> {
-        // TODO: avoid the aligned vec allocation here
-        let mut vec = rkyv::AlignedVec::new();
-        vec.extend_from_slice(slice);
-        let archived_value = unsafe { rkyv::archived_root::<Datasets>(vec.as_ref()) };
-        let inner = archived_value.deserialize(&mut rkyv::Infallible).unwrap();
-        Some(inner)
+        match slice.split_first() {
+            Some((&TAG_SMALL, rest)) => {
+                let ids = rest
+                    .chunks_exact(8)
+                    .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+                    .collect();
+                Some(Datasets::Small(ids))
+            }
+            Some((&TAG_ROARING, rest)) => {
+                RoaringTreemap::deserialize_from(rest).ok().map(Datasets::Roaring)
+            }
+            _ => None,
+        }
     }
 
     fn as_bytes(&self) -> Option<Vec<u8>> {
-        let bytes = rkyv::to_bytes::<_, 256>(self).unwrap();
-        Some(bytes.into_vec())
+        match self {
+            Datasets::Small(v) => {
+                let mut buf = Vec::with_capacity(1 + v.len() * 8);
+                buf.push(TAG_SMALL);
+                for id in v {
+                    buf.extend_from_slice(&id.to_le_bytes());
+                }
+                Some(buf)
+            }
+            Datasets::Roaring(r) => {
+                let mut buf = Vec::with_capacity(1 + r.serialized_size());
+                buf.push(TAG_ROARING);
+                r.serialize_into(&mut buf).ok()?;
+                Some(buf)
+            }
+        }
+    }
+}
 
-        /*
-        let mut serializer = DefaultSerializer::default();
-        let v = serializer.serialize_value(self).unwrap();
-        debug_assert_eq!(v, 0);
-        let buf = serializer.into_serializer().into_inner();
-        debug_assert!(Datasets::from_slice(&buf.to_vec()).is_some());
-        Some(buf.to_vec())
-        */
+impl IntoIterator for Datasets {
+    type Item = DatasetID;
+    type IntoIter = Box<dyn Iterator<Item = DatasetID>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        match self {
+            Datasets::Small(v) => Box::new(v.into_iter()),
+            Datasets::Roaring(r) => Box::new(r.into_iter().map(|id| id as DatasetID)),
+        }
     }
 }
 
@@ -262,20 +407,24 @@ fn index<P: AsRef<Path>>(
     template: Sketch,
     threshold: f64,
     output: P,
+    picklist: Option<Picklist>,
+    tuning: TuningOpts,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!("Loading siglist");
-    let index_sigs = read_paths(siglist)?;
+    let index_sigs = load_sigpaths(siglist, picklist.as_ref())?;
     info!("Loaded {} sig paths in siglist", index_sigs.len());
 
-    let mut opts = Options::default();
-    opts.create_if_missing(true);
-    opts.set_merge_operator_associative("datasets operator", merge_datasets);
+    let opts = build_options(&tuning);
     //opts.set_compaction_style(DBCompactionStyle::Universal);
     //opts.set_min_write_buffer_number_to_merge(10);
     {
-        let db = Arc::new(DB::open(&opts, output.as_ref()).unwrap());
+        let db = Arc::new(DB::open_cf(&opts, output.as_ref(), cf_names()).unwrap());
+        save_compression(output.as_ref(), &tuning.compression)?;
 
         let processed_sigs = AtomicUsize::new(0);
+        // Color updates are a non-associative read-modify-write, so they're
+        // serialized across the par_iter below via this lock.
+        let color_lock = Mutex::new(());
         let sig_iter = index_sigs.par_iter();
         //let sig_iter = index_sigs.iter();
 
@@ -291,8 +440,17 @@ fn index<P: AsRef<Path>>(
                     .unwrap_or_else(|_| panic!("Error processing {:?}", filename))
                     .swap_remove(0);
 
+                let cf_names = db.cf_handle(NAMES).unwrap();
+                db.put_cf(
+                    &cf_names,
+                    be_bytes(dataset_id as DatasetID),
+                    filename.to_str().unwrap().as_bytes(),
+                )
+                .expect("error writing name");
+
                 map_hashes_colors(
                     db.clone(),
+                    &color_lock,
                     dataset_id as DatasetID,
                     &search_sig,
                     threshold,
@@ -309,28 +467,45 @@ fn index<P: AsRef<Path>>(
 }
 
 fn check<P: AsRef<Path>>(output: P) -> Result<(), Box<dyn std::error::Error>> {
-    use byteorder::ReadBytesExt;
-
     let mut opts = Options::default();
     opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
     opts.set_merge_operator_associative("datasets operator", merge_datasets);
-    let db = Arc::new(DB::open_for_read_only(&opts, output.as_ref(), true)?);
+    opts.set_compression_type(compression_type(&load_compression(output.as_ref())));
+    // Opened writable so unreferenced colors can be reclaimed in place.
+    let db = Arc::new(DB::open_cf(&opts, output.as_ref(), cf_names())?);
 
-    let iter = db.iterator(rocksdb::IteratorMode::Start);
+    let cf_hashes = db.cf_handle(HASHES).unwrap();
+    let cf_colors = db.cf_handle(COLORS).unwrap();
+
+    // Tally how many hashes reference each color.
+    let mut references: HashMap<Color, usize> = HashMap::new();
     let mut kcount = 0;
-    let mut vcount = 0;
-    for (key, value) in iter {
-        let _k = (&key[..]).read_u64::<LittleEndian>()?;
+    for (key, value) in db.iterator_cf(&cf_hashes, rocksdb::IteratorMode::Start) {
         kcount += key.len();
-        //println!("Saw {} {:?}", k, Datasets::from_slice(&value));
-        let _v = Datasets::from_slice(&value).expect("Error with value");
+        let color = from_be_bytes(&value);
+        *references.entry(color).or_default() += 1;
+    }
+
+    // Walk the color table and reclaim any color no hash points at.
+    let mut vcount = 0;
+    let mut colors = 0;
+    let mut reclaimed = 0;
+    for (key, value) in db.iterator_cf(&cf_colors, rocksdb::IteratorMode::Start) {
         vcount += value.len();
-        //println!("Saw {} {:?}", k, value);
+        colors += 1;
+        let color = from_be_bytes(&key);
+        if !references.contains_key(&color) {
+            db.delete_cf(&cf_colors, &key)?;
+            reclaimed += 1;
+        }
     }
 
     use size::Size;
     let ksize = Size::from_bytes(kcount);
     let vsize = Size::from_bytes(vcount);
+    info!("hashes: {}, colors: {}", references.len(), colors);
+    info!("reclaimed {} unreferenced colors", reclaimed);
     info!("k: {}, v: {}", ksize.to_string(), vsize.to_string());
 
     Ok(())
@@ -338,55 +513,269 @@ fn check<P: AsRef<Path>>(output: P) -> Result<(), Box<dyn std::error::Error>> {
 
 fn search<P: AsRef<Path>>(
     queries_file: P,
-    siglist: P,
+    // Reference signatures were already indexed under their own DatasetID in
+    // the NAMES CF, so matches are resolved from there instead of re-deriving
+    // dataset_id -> path from this list's order, which isn't guaranteed to
+    // match the order signatures were indexed in.
+    _siglist: P,
     index: P,
     template: Sketch,
     threshold_bp: usize,
     output: Option<P>,
+    picklist: Option<Picklist>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut threshold = usize::max_value();
+    info!("Loading queries");
+    let query_files = load_sigpaths(&queries_file, picklist.as_ref())?;
+    info!("Loaded {} query paths", query_files.len());
+
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    opts.set_merge_operator_associative("datasets operator", merge_datasets);
+    opts.set_compression_type(compression_type(&load_compression(index.as_ref())));
+    let db = Arc::new(DB::open_cf_for_read_only(&opts, index.as_ref(), cf_names(), true)?);
+    info!("Loaded DB");
+
+    // Each query builds its own counter against the shared read-only DB; the
+    // queries are independent so they run in parallel.
+    let results: Vec<(String, Vec<String>)> = query_files
+        .par_iter()
+        .filter_map(|query_path| {
+            let query_sig = match Signature::from_path(query_path) {
+                Ok(sig) => sig,
+                Err(e) => {
+                    info!("Skipping query {:?}: {}", query_path, e);
+                    return None;
+                }
+            };
+            let mut query = None;
+            let mut threshold = usize::max_value();
+            for sig in &query_sig {
+                if let Some(Sketch::MinHash(mh)) = sig.select_sketch(&template) {
+                    query = Some(mh.clone());
+                    // TODO: deal with mh.size() == 0
+                    let t = threshold_bp / (cmp::max(mh.size(), 1) * mh.scaled() as usize);
+                    threshold = cmp::min(threshold, t);
+                }
+            }
+            let query = match query {
+                Some(query) => query,
+                None => {
+                    info!("Skipping query {:?}: no compatible MinHash", query_path);
+                    return None;
+                }
+            };
+
+            let counter = counter_for_query(db.clone(), &query);
+
+            let mut matches = vec![];
+            for (dataset_id, size) in counter.most_common() {
+                if size >= threshold {
+                    match name_for_dataset(&db, dataset_id) {
+                        Some(name) => matches.push(name),
+                        None => info!("No name recorded for dataset {}", dataset_id),
+                    }
+                } else {
+                    break;
+                }
+            }
+            Some((query_path.to_str().unwrap().to_string(), matches))
+        })
+        .collect();
+
+    if let Some(output) = output {
+        let mut writer = csv::Writer::from_path(output)?;
+        writer.write_record(["query", "match"])?;
+        for (query, matches) in &results {
+            for m in matches {
+                writer.write_record([query, m])?;
+            }
+        }
+        writer.flush()?;
+    } else {
+        for (query, matches) in &results {
+            info!("{}: {:?}", query, matches);
+        }
+    }
+
+    Ok(())
+}
+
+/// One non-redundant match emitted by [`gather`].
+#[derive(Debug)]
+struct GatherResult {
+    match_name: String,
+    intersect_bp: usize,
+    f_match: f64,
+    remaining_bp: usize,
+}
 
+fn gather<P: AsRef<Path>>(
+    queries_file: P,
+    // Reference signatures were already indexed under their own DatasetID in
+    // the NAMES CF, so matches are resolved from there instead of re-deriving
+    // dataset_id -> path from this list's order, which isn't guaranteed to
+    // match the order signatures were indexed in.
+    _siglist: P,
+    index: P,
+    template: Sketch,
+    threshold_bp: usize,
+    output: Option<P>,
+) -> Result<(), Box<dyn std::error::Error>> {
     let query_sig = Signature::from_path(queries_file)?;
     let mut query = None;
     for sig in &query_sig {
-        if let Some(sketch) = sig.select_sketch(&template) {
-            if let Sketch::MinHash(mh) = sketch {
-                query = Some(mh.clone());
-                // TODO: deal with mh.size() == 0
-                let t = threshold_bp / (cmp::max(mh.size(), 1) * mh.scaled() as usize);
-                threshold = cmp::min(threshold, t);
-            }
+        if let Some(Sketch::MinHash(mh)) = sig.select_sketch(&template) {
+            query = Some(mh.clone());
         }
     }
-    let query = query.unwrap();
-
-    info!("Loading siglist");
-    let sig_files = read_paths(siglist)?;
-    info!("Loaded {} sig paths in siglist", sig_files.len());
+    let query = query.expect("Couldn't find a compatible MinHash in query");
+    let scaled = cmp::max(query.scaled() as usize, 1);
+    // Minimum intersection, in hashes, for a match to be worth emitting.
+    let threshold = threshold_bp / scaled;
 
     let mut opts = Options::default();
     opts.create_if_missing(true);
     opts.set_merge_operator_associative("datasets operator", merge_datasets);
-    let db = Arc::new(DB::open_for_read_only(&opts, index.as_ref(), true)?);
+    opts.set_compression_type(compression_type(&load_compression(index.as_ref())));
+    let db = Arc::new(DB::open_cf_for_read_only(&opts, index.as_ref(), cf_names(), true)?);
     info!("Loaded DB");
 
     info!("Building counter");
-    let counter = counter_for_query(db, &query);
-    info!("Counter built");
+    let mut counter = counter_for_query(db.clone(), &query);
 
-    let mut matches: Vec<String> = vec![];
-    for (dataset_id, size) in counter.most_common() {
-        if size >= threshold {
-            matches.push(sig_files[dataset_id as usize].to_str().unwrap().into());
-        } else {
+    // Hashes of the query not yet explained by an emitted match.
+    let mut remaining: HashSet<u64> = query.mins().into_iter().collect();
+
+    let mut matches = vec![];
+    while let Some((dataset_id, &size)) = counter.most_common().first().map(|(d, c)| (*d, c)) {
+        if size < threshold || remaining.is_empty() {
             break;
-        };
+        }
+
+        let match_name = name_for_dataset(&db, dataset_id)
+            .unwrap_or_else(|| panic!("No name recorded for dataset {}", dataset_id));
+        let match_sig = Signature::from_path(&match_name)?.swap_remove(0);
+        let match_mh = match_sig
+            .select_sketch(&template)
+            .and_then(|s| match s {
+                Sketch::MinHash(mh) => Some(mh.clone()),
+                _ => None,
+            })
+            .expect("Couldn't find a compatible MinHash in match");
+
+        // Hashes this match accounts for out of what remains.
+        let intersection: Vec<u64> = match_mh
+            .mins()
+            .into_iter()
+            .filter(|h| remaining.contains(h))
+            .collect();
+
+        matches.push(GatherResult {
+            match_name,
+            intersect_bp: intersection.len() * scaled,
+            f_match: intersection.len() as f64 / cmp::max(match_mh.size(), 1) as f64,
+            remaining_bp: remaining.len() * scaled,
+        });
+
+        // Re-resolve each removed hash's color and then the color's Datasets
+        // (as counter_for_query does), so we know which other datasets shared
+        // it, and decrement their counts before the next iteration.
+        let cf_hashes = db.cf_handle(HASHES).unwrap();
+        let cf_colors = db.cf_handle(COLORS).unwrap();
+
+        let color_keys = intersection.iter().map(|&hash| (&cf_hashes, be_bytes(hash)));
+        let colors: Vec<Color> = db
+            .multi_get_cf(color_keys)
+            .into_iter()
+            .filter_map(|r| r.ok().flatten().map(|b| from_be_bytes(&b)))
+            .collect();
+
+        let ds_keys = colors.iter().map(|&color| (&cf_colors, be_bytes(color)));
+        for raw in db.multi_get_cf(ds_keys).into_iter().flatten().flatten() {
+            for other in Datasets::from_slice(&raw).unwrap() {
+                if let Some(c) = counter.get_mut(&other) {
+                    *c = c.saturating_sub(1);
+                }
+            }
+        }
+
+        for hash in &intersection {
+            remaining.remove(hash);
+        }
+        counter.remove(&dataset_id);
+    }
+
+    if let Some(output) = output {
+        let mut writer = csv::Writer::from_path(output)?;
+        writer.write_record(["match", "intersect_bp", "f_match", "remaining_bp"])?;
+        for result in &matches {
+            writer.write_record([
+                result.match_name.clone(),
+                result.intersect_bp.to_string(),
+                result.f_match.to_string(),
+                result.remaining_bp.to_string(),
+            ])?;
+        }
+        writer.flush()?;
+    } else {
+        for result in &matches {
+            info!(
+                "{}: intersect_bp={} f_match={:.3} remaining_bp={}",
+                result.match_name, result.intersect_bp, result.f_match, result.remaining_bp
+            );
+        }
     }
-    info!("{:?}", matches);
 
     Ok(())
 }
 
+fn backup<P: AsRef<Path>>(index: P, dest: P) -> Result<(), Box<dyn std::error::Error>> {
+    use rocksdb::backup::{BackupEngine, BackupEngineOptions};
+
+    let mut opts = Options::default();
+    opts.create_missing_column_families(true);
+    opts.set_merge_operator_associative("datasets operator", merge_datasets);
+    // Opened read-only so a backup can be taken while the index is also open
+    // for indexing or search elsewhere.
+    let db = DB::open_cf_for_read_only(&opts, index.as_ref(), cf_names(), true)?;
+
+    let env = rocksdb::Env::new()?;
+    let be_opts = BackupEngineOptions::new(dest.as_ref())?;
+    let mut engine = BackupEngine::open(&be_opts, &env)?;
+    engine.create_new_backup(&db)?;
+    info!("Created backup of {:?} in {:?}", index.as_ref(), dest.as_ref());
+    Ok(())
+}
+
+fn checkpoint<P: AsRef<Path>>(index: P, dest: P) -> Result<(), Box<dyn std::error::Error>> {
+    use rocksdb::checkpoint::Checkpoint;
+
+    let mut opts = Options::default();
+    opts.create_missing_column_families(true);
+    opts.set_merge_operator_associative("datasets operator", merge_datasets);
+    // Opened read-only so a checkpoint can be taken while the index is also
+    // open for indexing or search elsewhere.
+    let db = DB::open_cf_for_read_only(&opts, index.as_ref(), cf_names(), true)?;
+
+    let checkpoint = Checkpoint::new(&db)?;
+    checkpoint.create_checkpoint(dest.as_ref())?;
+    info!("Created checkpoint of {:?} in {:?}", index.as_ref(), dest.as_ref());
+    Ok(())
+}
+
+fn restore<P: AsRef<Path>>(from: P, index: P) -> Result<(), Box<dyn std::error::Error>> {
+    use rocksdb::backup::{BackupEngine, BackupEngineOptions, RestoreOptions};
+
+    let env = rocksdb::Env::new()?;
+    let be_opts = BackupEngineOptions::new(from.as_ref())?;
+    let mut engine = BackupEngine::open(&be_opts, &env)?;
+    let mut restore_opts = RestoreOptions::default();
+    restore_opts.set_keep_log_files(false);
+    engine.restore_from_latest_backup(index.as_ref(), index.as_ref(), &restore_opts)?;
+    info!("Restored {:?} from backup {:?}", index.as_ref(), from.as_ref());
+    Ok(())
+}
+
 fn read_paths<P: AsRef<Path>>(paths_file: P) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
     let paths = BufReader::new(File::open(paths_file)?);
     Ok(paths
@@ -399,6 +788,145 @@ fn read_paths<P: AsRef<Path>>(paths_file: P) -> Result<Vec<PathBuf>, Box<dyn std
         .collect())
 }
 
+/// One row of a sourmash-style manifest CSV.
+#[derive(Debug, Clone, serde::Deserialize)]
+struct ManifestRow {
+    internal_location: String,
+    md5: String,
+    ksize: u32,
+    moltype: String,
+    scaled: u64,
+    n_hashes: u64,
+    name: String,
+}
+
+/// A picklist loaded from `FILE:COLUMN:MATCH`: keep signatures whose `MATCH`
+/// field (`md5` or `name`) is one of the values found in column `COLUMN` of the
+/// CSV at `FILE`.
+struct Picklist {
+    field: String,
+    values: HashSet<String>,
+}
+
+impl Picklist {
+    fn from_arg(spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let parts: Vec<&str> = spec.splitn(3, ':').collect();
+        if parts.len() != 3 {
+            return Err("picklist must be FILE:COLUMN:MATCH".into());
+        }
+        let (file, column, field) = (parts[0], parts[1], parts[2].to_string());
+
+        let mut reader = csv::Reader::from_path(file)?;
+        let headers = reader.headers()?.clone();
+        let col_idx = headers
+            .iter()
+            .position(|h| h == column)
+            .ok_or_else(|| format!("column {} not found in picklist", column))?;
+
+        let mut values = HashSet::new();
+        for record in reader.records() {
+            let record = record?;
+            if let Some(v) = record.get(col_idx) {
+                values.insert(v.to_string());
+            }
+        }
+        Ok(Picklist { field, values })
+    }
+
+    fn matches(&self, row: &ManifestRow) -> bool {
+        let value = match self.field.as_str() {
+            "md5" => &row.md5,
+            "name" => &row.name,
+            _ => return true,
+        };
+        self.values.contains(value)
+    }
+}
+
+/// Load signature paths from either a newline-delimited path list or a manifest
+/// CSV (detected by extension), optionally filtered by `picklist`.
+fn load_sigpaths<P: AsRef<Path>>(
+    siglist: P,
+    picklist: Option<&Picklist>,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let is_csv = siglist
+        .as_ref()
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("csv"));
+
+    if !is_csv {
+        return read_paths(siglist);
+    }
+
+    let mut reader = csv::Reader::from_path(siglist)?;
+    let mut paths = vec![];
+    for row in reader.deserialize() {
+        let row: ManifestRow = row?;
+        if picklist.map_or(true, |p| p.matches(&row)) {
+            paths.push(PathBuf::from(&row.internal_location));
+        }
+    }
+    Ok(paths)
+}
+
+/// Tuning knobs for the RocksDB `Options`/`BlockBasedOptions`, exposed on the
+/// CLI so large indices can trade CPU for disk.
+#[derive(Debug, Clone)]
+struct TuningOpts {
+    compression: String,
+    block_size: Option<usize>,
+    write_buffer_size: Option<usize>,
+    bloom_bits: Option<i32>,
+}
+
+fn compression_type(name: &str) -> rocksdb::DBCompressionType {
+    match name {
+        "lz4" => rocksdb::DBCompressionType::Lz4,
+        "zstd" => rocksdb::DBCompressionType::Zstd,
+        _ => rocksdb::DBCompressionType::None,
+    }
+}
+
+/// Build the DB `Options`, applying the merge operator and any tuning knobs.
+fn build_options(tuning: &TuningOpts) -> Options {
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    opts.set_merge_operator_associative("datasets operator", merge_datasets);
+
+    opts.set_compression_type(compression_type(&tuning.compression));
+    if let Some(size) = tuning.write_buffer_size {
+        opts.set_write_buffer_size(size);
+    }
+
+    if tuning.block_size.is_some() || tuning.bloom_bits.is_some() {
+        let mut block_opts = rocksdb::BlockBasedOptions::default();
+        if let Some(size) = tuning.block_size {
+            block_opts.set_block_size(size);
+        }
+        if let Some(bits) = tuning.bloom_bits {
+            block_opts.set_bloom_filter(bits as f64, false);
+        }
+        opts.set_block_based_table_factory(&block_opts);
+    }
+
+    opts
+}
+
+/// Persist the compression choice alongside the index so `search`/`check`
+/// reopen it with the matching codec.
+fn save_compression(index: &Path, compression: &str) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(index.join("COMPRESSION"), compression)?;
+    Ok(())
+}
+
+/// Load the persisted compression choice, defaulting to none.
+fn load_compression(index: &Path) -> String {
+    std::fs::read_to_string(index.join("COMPRESSION"))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|_| "none".to_string())
+}
+
 fn build_template(ksize: u8, scaled: usize) -> Sketch {
     let max_hash = max_hash_for_scaled(scaled as u64);
     let template_mh = KmerMinHash::builder()
@@ -438,6 +966,26 @@ enum Commands {
         /// The path for output
         #[clap(parse(from_os_str), short, long)]
         output: PathBuf,
+
+        /// Picklist to restrict indexing, as FILE:COLUMN:MATCH
+        #[clap(long)]
+        picklist: Option<String>,
+
+        /// Compression codec for the index
+        #[clap(long, default_value = "none", possible_values = ["none", "lz4", "zstd"])]
+        compression: String,
+
+        /// Block size in bytes for the block-based table
+        #[clap(long)]
+        block_size: Option<usize>,
+
+        /// Write buffer (memtable) size in bytes
+        #[clap(long)]
+        write_buffer_size: Option<usize>,
+
+        /// Bits per key for the block-based bloom filter
+        #[clap(long)]
+        bloom_bits: Option<i32>,
     },
     Check {
         /// The path for output
@@ -472,6 +1020,67 @@ enum Commands {
         /// The path for output
         #[clap(parse(from_os_str), short = 'o', long = "output")]
         output: Option<PathBuf>,
+
+        /// Picklist to restrict the query set, as FILE:COLUMN:MATCH; matches
+        /// against the index are not filtered
+        #[clap(long)]
+        picklist: Option<String>,
+    },
+    Gather {
+        /// Query signature
+        #[clap(parse(from_os_str))]
+        query_path: PathBuf,
+
+        /// Precomputed index or list of reference signatures
+        #[clap(parse(from_os_str))]
+        siglist: PathBuf,
+
+        /// Precomputed index or list of reference signatures
+        #[clap(parse(from_os_str))]
+        index: PathBuf,
+
+        /// ksize
+        #[clap(short = 'k', long = "ksize", default_value = "31")]
+        ksize: u8,
+
+        /// scaled
+        #[clap(short = 's', long = "scaled", default_value = "1000")]
+        scaled: usize,
+
+        /// threshold_bp
+        #[clap(short = 't', long = "threshold_bp", default_value = "50000")]
+        threshold_bp: usize,
+
+        /// The path for output
+        #[clap(parse(from_os_str), short = 'o', long = "output")]
+        output: Option<PathBuf>,
+    },
+    Backup {
+        /// Index to back up
+        #[clap(parse(from_os_str), long)]
+        index: PathBuf,
+
+        /// Destination backup directory
+        #[clap(parse(from_os_str), long)]
+        dest: PathBuf,
+    },
+    Checkpoint {
+        /// Index to checkpoint
+        #[clap(parse(from_os_str), long)]
+        index: PathBuf,
+
+        /// Destination checkpoint directory
+        #[clap(parse(from_os_str), long)]
+        dest: PathBuf,
+    },
+    Restore {
+        /// Backup directory to restore from
+        #[clap(parse(from_os_str), long)]
+        from: PathBuf,
+
+        /// Destination index path
+        #[clap(parse(from_os_str), long)]
+        index: PathBuf,
     },
 }
 
@@ -488,10 +1097,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             threshold,
             ksize,
             scaled,
+            picklist,
+            compression,
+            block_size,
+            write_buffer_size,
+            bloom_bits,
         } => {
             let template = build_template(ksize, scaled);
 
-            index(siglist, template, threshold, output)?
+            let picklist = picklist.map(|s| Picklist::from_arg(&s)).transpose()?;
+            let tuning = TuningOpts {
+                compression,
+                block_size,
+                write_buffer_size,
+                bloom_bits,
+            };
+            index(siglist, template, threshold, output, picklist, tuning)?
         }
         Check { output } => check(output)?,
         Search {
@@ -502,11 +1123,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             threshold_bp,
             ksize,
             scaled,
+            picklist,
+        } => {
+            let template = build_template(ksize, scaled);
+
+            let picklist = picklist.map(|s| Picklist::from_arg(&s)).transpose()?;
+            search(query_path, siglist, index, template, threshold_bp, output, picklist)?
+        }
+        Gather {
+            query_path,
+            output,
+            siglist,
+            index,
+            threshold_bp,
+            ksize,
+            scaled,
         } => {
             let template = build_template(ksize, scaled);
 
-            search(query_path, siglist, index, template, threshold_bp, output)?
+            gather(query_path, siglist, index, template, threshold_bp, output)?
         }
+        Backup { index, dest } => backup(index, dest)?,
+        Checkpoint { index, dest } => checkpoint(index, dest)?,
+        Restore { from, index } => restore(from, index)?,
     };
 
     Ok(())
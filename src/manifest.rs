@@ -0,0 +1,98 @@
+use std::path::{Path, PathBuf};
+
+use rkyv::{Archive, Deserialize, Serialize};
+use serde::Deserialize as SerdeDeserialize;
+
+/// Column family holding one manifest row per `DatasetID`.
+pub const MANIFEST: &str = "manifest";
+
+/// A single row of a sourmash-style CSV manifest.
+#[derive(Debug, Clone, PartialEq, Archive, Serialize, Deserialize, SerdeDeserialize)]
+pub struct ManifestRow {
+    pub md5: String,
+    pub ksize: u32,
+    pub moltype: String,
+    pub scaled: u64,
+    pub num: u32,
+    pub filename: String,
+    pub name: String,
+}
+
+impl ManifestRow {
+    pub fn from_slice(slice: &[u8]) -> Option<Self> {
+        let mut vec = rkyv::AlignedVec::new();
+        vec.extend_from_slice(slice);
+        let archived_value = unsafe { rkyv::archived_root::<ManifestRow>(vec.as_ref()) };
+        let inner = archived_value.deserialize(&mut rkyv::Infallible).unwrap();
+        Some(inner)
+    }
+
+    pub fn as_bytes(&self) -> Option<Vec<u8>> {
+        let bytes = rkyv::to_bytes::<_, 256>(self).unwrap();
+        Some(bytes.into_vec())
+    }
+}
+
+/// An in-memory manifest of signatures available to index or query.
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    rows: Vec<ManifestRow>,
+}
+
+impl Manifest {
+    /// Load a manifest from a CSV with columns
+    /// `md5,ksize,moltype,scaled,num,filename,name`.
+    pub fn from_csv<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let mut rows = Vec::new();
+        for row in reader.deserialize() {
+            rows.push(row?);
+        }
+        Ok(Manifest { rows })
+    }
+
+    pub fn rows(&self) -> &[ManifestRow] {
+        &self.rows
+    }
+
+    /// Signature paths in manifest order, optionally restricted by `picklist`.
+    pub fn paths(&self, picklist: Option<&Picklist>) -> Vec<PathBuf> {
+        self.rows
+            .iter()
+            .filter(|row| picklist.map_or(true, |p| p.matches(row)))
+            .map(|row| PathBuf::from(&row.filename))
+            .collect()
+    }
+}
+
+/// Which column of a manifest a [`Picklist`] filters on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PicklistColumn {
+    Md5,
+    Name,
+    Ksize,
+    Moltype,
+}
+
+/// A filter over manifest rows, mirroring upstream sourmash selection.
+#[derive(Debug, Clone)]
+pub struct Picklist {
+    column: PicklistColumn,
+    values: Vec<String>,
+}
+
+impl Picklist {
+    pub fn new(column: PicklistColumn, values: Vec<String>) -> Self {
+        Picklist { column, values }
+    }
+
+    /// Keep rows whose selected column matches the picklist.
+    pub fn matches(&self, row: &ManifestRow) -> bool {
+        match self.column {
+            PicklistColumn::Md5 => self.values.iter().any(|v| v == &row.md5),
+            PicklistColumn::Name => self.values.iter().any(|v| row.name.contains(v.as_str())),
+            PicklistColumn::Ksize => self.values.iter().any(|v| v == &row.ksize.to_string()),
+            PicklistColumn::Moltype => self.values.iter().any(|v| v == &row.moltype),
+        }
+    }
+}
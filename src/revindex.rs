@@ -0,0 +1,393 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use log::info;
+use rayon::prelude::*;
+use rocksdb::{MergeOperands, Options};
+
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::bloom::{Bloom, HASHES_BLOOM};
+use crate::manifest::{ManifestRow, Picklist, MANIFEST};
+use crate::{
+    be_bytes, from_be_bytes, sig_save_to_db, DatasetID, Datasets, GatherResult, HashToColor,
+    QueryColors, SigCounter, SignatureData, DB, HASHES, SIGS,
+};
+
+/// Key under which the HASHES_BLOOM CF stores the single serialized filter.
+const BLOOM_KEY: &[u8] = b"bloom";
+const BLOOM_FP_RATE: f64 = 0.01;
+
+/// Column families opened for a plain (non-colored) index.
+fn cf_names() -> Vec<&'static str> {
+    vec![HASHES, SIGS, HASHES_BLOOM, MANIFEST]
+}
+
+/// Associative merge operator: folds each operand `Datasets` into whatever is
+/// already stored, so concurrent datasets sharing a hash merge safely without
+/// external locking.
+fn merge_datasets(_: &[u8], existing_val: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
+    let mut datasets = existing_val.and_then(Datasets::from_slice).unwrap_or_default();
+    for op in operands {
+        let new_vals = Datasets::from_slice(op).unwrap();
+        datasets.union(new_vals);
+    }
+    datasets.as_bytes()
+}
+
+/// A `RevIndex` that stores the full `Datasets` set directly under each hash
+/// in the HASHES CF, with no color indirection.
+pub struct RevIndex {
+    db: Arc<DB>,
+}
+
+impl RevIndex {
+    pub fn open(index: &Path, read_only: bool) -> crate::RevIndex {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        opts.set_merge_operator_associative("datasets operator", merge_datasets);
+
+        let db = if read_only {
+            Arc::new(
+                DB::open_cf_for_read_only(&opts, index, cf_names(), true)
+                    .expect("error opening database"),
+            )
+        } else {
+            Arc::new(DB::open_cf(&opts, index, cf_names()).expect("error opening database"))
+        };
+
+        crate::RevIndex::Plain(RevIndex { db })
+    }
+
+    pub fn index(
+        &self,
+        index_sigs: Vec<PathBuf>,
+        template: &Sketch,
+        threshold: f64,
+        save_paths: bool,
+        picklist: Option<&Picklist>,
+    ) {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let cf_manifest = self.db.cf_handle(MANIFEST).unwrap();
+        let processed_sigs = AtomicUsize::new(0);
+
+        index_sigs.par_iter().enumerate().for_each(|(dataset_id, filename)| {
+            let dataset_id = dataset_id as DatasetID;
+            let i = processed_sigs.fetch_add(1, Ordering::SeqCst);
+            if i % 1000 == 0 {
+                info!("Processed {} reference sigs", i);
+            }
+
+            let search_sig = Signature::from_path(filename)
+                .unwrap_or_else(|_| panic!("Error processing {:?}", filename))
+                .swap_remove(0);
+
+            let mut search_mh = None;
+            if let Some(Sketch::MinHash(mh)) = search_sig.select_sketch(template) {
+                search_mh = Some(mh.clone());
+            }
+            let search_mh = search_mh.expect("Couldn't find a compatible MinHash");
+            let size = search_mh.size() as u64;
+
+            let row = ManifestRow {
+                md5: search_sig.md5sum(),
+                ksize: search_mh.ksize() as u32,
+                moltype: "DNA".to_string(),
+                scaled: search_mh.scaled(),
+                num: search_mh.num(),
+                filename: filename.to_str().unwrap().to_string(),
+                name: search_sig.name(),
+            };
+            if !picklist.map_or(true, |p| p.matches(&row)) {
+                return;
+            }
+            self.db
+                .put_cf(&cf_manifest, be_bytes(dataset_id), row.as_bytes().unwrap())
+                .expect("error writing manifest row");
+
+            let ds = Datasets::new(&[dataset_id]);
+            for hash in search_mh.mins() {
+                self.db
+                    .merge_cf(&cf_hashes, be_bytes(hash), ds.as_bytes().unwrap())
+                    .expect("error merging hash datasets");
+            }
+
+            sig_save_to_db(
+                self.db.clone(),
+                search_sig,
+                search_mh,
+                size,
+                threshold,
+                save_paths,
+                filename,
+                dataset_id,
+            );
+        });
+
+        info!("Processed {} reference sigs", processed_sigs.into_inner());
+
+        self.rebuild_bloom();
+    }
+
+    /// Rebuild the hash bloom filter from the full HASHES CF, sized from the
+    /// actual number of indexed hashes. Rebuilding from the whole CF (rather
+    /// than just the hashes touched by this call) keeps incremental `index()`
+    /// calls from persisting a filter that only covers the latest batch and
+    /// silently treats earlier-batch hashes as definitely absent.
+    fn rebuild_bloom(&self) {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let cf_bloom = self.db.cf_handle(HASHES_BLOOM).unwrap();
+
+        let num_hashes = self
+            .db
+            .iterator_cf(&cf_hashes, rocksdb::IteratorMode::Start)
+            .count() as u64;
+
+        let mut bloom = Bloom::new(num_hashes, BLOOM_FP_RATE);
+        for (key, _) in self
+            .db
+            .iterator_cf(&cf_hashes, rocksdb::IteratorMode::Start)
+            .flatten()
+        {
+            bloom.insert(from_be_bytes(&key));
+        }
+
+        self.db
+            .put_cf(&cf_bloom, BLOOM_KEY, bloom.as_bytes().unwrap())
+            .expect("error persisting bloom filter");
+    }
+
+    /// Load the persisted hash bloom filter, if the index has one.
+    fn load_bloom(&self) -> Option<Bloom> {
+        let cf_bloom = self.db.cf_handle(HASHES_BLOOM)?;
+        self.db
+            .get_cf(&cf_bloom, BLOOM_KEY)
+            .ok()
+            .flatten()
+            .and_then(|b| Bloom::from_slice(&b))
+    }
+
+    pub fn counter_for_query(&self, query: &KmerMinHash) -> SigCounter {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+
+        // Skip the RocksDB round-trip entirely for hashes the bloom filter
+        // says are definitely absent from this index.
+        let bloom = self.load_bloom();
+        let keys = query
+            .iter_mins()
+            .filter(|&hash| bloom.as_ref().map_or(true, |b| b.contains(hash)))
+            .map(|hash| (&cf_hashes, be_bytes(hash)));
+
+        self.db
+            .multi_get_cf(keys)
+            .into_iter()
+            .filter_map(|r| r.ok().flatten())
+            .filter_map(|raw| Datasets::from_slice(&raw))
+            .flat_map(|ds| ds.into_iter())
+            .collect()
+    }
+
+    pub fn matches_from_counter(self, counter: SigCounter, threshold: usize) -> Vec<String> {
+        let cf_sigs = self.db.cf_handle(SIGS).unwrap();
+
+        let ids: Vec<DatasetID> = counter
+            .most_common()
+            .into_iter()
+            .filter(|(_, size)| *size >= threshold)
+            .map(|(id, _)| id)
+            .collect();
+
+        let keys = ids.iter().map(|&id| (&cf_sigs, be_bytes(id)));
+        self.db
+            .multi_get_cf(keys)
+            .into_iter()
+            .filter_map(|raw| raw.ok().flatten())
+            .filter_map(|raw| SignatureData::from_slice(&raw))
+            .filter_map(|sig| match sig {
+                SignatureData::External(path) => Some(path),
+                SignatureData::Internal(sig) => Some(sig.name()),
+                SignatureData::Empty => None,
+            })
+            .collect()
+    }
+
+    /// The plain index has no color indirection, so both maps are empty;
+    /// `gather` falls back to resolving `Datasets` straight from HASHES.
+    pub fn prepare_gather_counters(
+        &self,
+        query: &KmerMinHash,
+    ) -> (SigCounter, QueryColors, HashToColor) {
+        (self.counter_for_query(query), QueryColors::new(), HashToColor::new())
+    }
+
+    pub fn gather(
+        &self,
+        mut counter: SigCounter,
+        _query_colors: QueryColors,
+        _hash_to_color: HashToColor,
+        threshold: usize,
+        query: &KmerMinHash,
+        template: &Sketch,
+    ) -> Result<Vec<GatherResult>, Box<dyn std::error::Error>> {
+        let cf_sigs = self.db.cf_handle(SIGS).unwrap();
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+
+        let scaled = cmp::max(query.scaled() as usize, 1);
+        let mut remaining: HashSet<u64> = query.mins().into_iter().collect();
+        let mut matches = Vec::new();
+
+        while let Some((dataset_id, &size)) = counter.most_common().first().map(|(d, c)| (*d, c)) {
+            if size < threshold || remaining.is_empty() {
+                break;
+            }
+
+            let raw = self
+                .db
+                .get_cf(&cf_sigs, be_bytes(dataset_id))?
+                .expect("dataset must have a signature entry");
+            let match_name = match SignatureData::from_slice(&raw) {
+                Some(SignatureData::External(path)) => path,
+                Some(SignatureData::Internal(ref sig)) => sig.name(),
+                _ => panic!("dataset {} has no usable signature", dataset_id),
+            };
+
+            let match_mh = match SignatureData::from_slice(&raw) {
+                Some(SignatureData::Internal(sig)) => sig
+                    .select_sketch(template)
+                    .and_then(|s| match s {
+                        Sketch::MinHash(mh) => Some(mh),
+                        _ => None,
+                    })
+                    .expect("Couldn't find a compatible MinHash in match"),
+                _ => {
+                    let match_sig = Signature::from_path(&match_name)?.swap_remove(0);
+                    match_sig
+                        .select_sketch(template)
+                        .and_then(|s| match s {
+                            Sketch::MinHash(mh) => Some(mh.clone()),
+                            _ => None,
+                        })
+                        .expect("Couldn't find a compatible MinHash in match")
+                }
+            };
+
+            let intersection: Vec<u64> = match_mh
+                .mins()
+                .into_iter()
+                .filter(|h| remaining.contains(h))
+                .collect();
+
+            matches.push(GatherResult {
+                match_name,
+                intersect_bp: intersection.len() * scaled,
+                f_match: intersection.len() as f64 / cmp::max(match_mh.size(), 1) as f64,
+                remaining_bp: remaining.len() * scaled,
+            });
+
+            // Re-resolve the datasets sharing each removed hash and
+            // decrement their counts before the next iteration.
+            let keys = intersection.iter().map(|&hash| (&cf_hashes, be_bytes(hash)));
+            for raw in self.db.multi_get_cf(keys).into_iter().flatten().flatten() {
+                for other in Datasets::from_slice(&raw).unwrap_or_default() {
+                    if let Some(c) = counter.get_mut(&other) {
+                        *c = c.saturating_sub(1);
+                    }
+                }
+            }
+
+            for hash in &intersection {
+                remaining.remove(hash);
+            }
+            counter.remove(&dataset_id);
+        }
+
+        Ok(matches)
+    }
+
+    pub fn compact(&self) {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        self.db.compact_range_cf(&cf_hashes, None::<&[u8]>, None::<&[u8]>);
+    }
+
+    pub fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn check(&self, _quick: bool) {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let mut kcount = 0;
+        let mut datasets = 0;
+        for (key, value) in self.db.iterator_cf(&cf_hashes, rocksdb::IteratorMode::Start) {
+            kcount += key.len();
+            if let Some(ds) = Datasets::from_slice(&value) {
+                datasets += ds.len();
+            }
+        }
+        info!("hashes: {}, total dataset references: {}", kcount / 8, datasets);
+    }
+
+    pub fn checkpoint(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let checkpoint = rocksdb::checkpoint::Checkpoint::new(&*self.db)?;
+        checkpoint.create_checkpoint(path)?;
+        Ok(())
+    }
+
+    pub fn backup(&self, engine_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        use rocksdb::backup::{BackupEngine, BackupEngineOptions};
+
+        let env = rocksdb::Env::new()?;
+        let be_opts = BackupEngineOptions::new(engine_path)?;
+        let mut engine = BackupEngine::open(&be_opts, &env)?;
+        engine.create_new_backup(&*self.db)?;
+        Ok(())
+    }
+
+    /// Iterate the hashes present in the HASHES CF whose values fall within
+    /// `[min_hash, max_hash]`, relying on the big-endian key encoding so a
+    /// forward seek from `min_hash` yields them in ascending order.
+    pub fn hashes_in_range(&self, min_hash: u64, max_hash: u64) -> Vec<u64> {
+        let cf_hashes = self.db.cf_handle(HASHES).unwrap();
+        let start = be_bytes(min_hash);
+        let iter = self.db.iterator_cf(
+            &cf_hashes,
+            rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward),
+        );
+
+        let mut out = Vec::new();
+        for (key, _) in iter {
+            let hash = from_be_bytes(&key);
+            if hash > max_hash {
+                break;
+            }
+            out.push(hash);
+        }
+        out
+    }
+
+    /// Iterate the dataset IDs stored in the SIGS CF within `[lo, hi]`.
+    pub fn datasets_in_range(&self, lo: DatasetID, hi: DatasetID) -> Vec<DatasetID> {
+        let cf_sigs = self.db.cf_handle(SIGS).unwrap();
+        let start = be_bytes(lo);
+        let iter = self.db.iterator_cf(
+            &cf_sigs,
+            rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward),
+        );
+
+        let mut out = Vec::new();
+        for (key, _) in iter {
+            let id = from_be_bytes(&key);
+            if id > hi {
+                break;
+            }
+            out.push(id);
+        }
+        out
+    }
+}